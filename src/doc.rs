@@ -1,10 +1,8 @@
-use std::{
-    collections::HashSet,
-    fs::File,
-    io::{self, BufRead, BufReader},
-};
+use std::{collections::HashSet, io};
 
-use lsp_types::{Position, Range, Uri};
+use lsp_types::{
+    FoldingRange, FoldingRangeKind, Position, Range, TextDocumentContentChangeEvent, Uri,
+};
 use smallvec::SmallVec;
 use smol_str::SmolStr;
 
@@ -15,49 +13,154 @@ pub struct Document {
     pub lines: Vec<String>,
     pub imports: HashSet<Ident>,
     pub items: HashSet<HareItem>,
+    /// Byte offset of the start of each line, as if `lines` were joined by `\n`
+    /// into a single buffer. Used to translate LSP `Position`s into offsets
+    /// for incremental edits.
+    line_offsets: Vec<usize>,
 }
 
 impl Document {
     pub fn open(uri: &Uri) -> io::Result<Self> {
-        let file = File::open(uri.path().as_str())?;
-        // eprintln!("INFO: added doc: {}", entry_path.display());
-        let lines = BufReader::new(file)
-            .lines()
-            .collect::<Result<Vec<String>, _>>()?;
-        let items = parse_items(&lines);
-        let imports = get_imports(&lines);
-        Ok(Document {
-            lines,
-            items,
-            imports,
-        })
+        // `split`, not `lines`, so a trailing `\n` keeps its trailing empty
+        // line and the line table matches the client's for this file.
+        let content = std::fs::read_to_string(uri.path().as_str())?;
+        let lines = content.split('\n').map(String::from).collect();
+        Ok(Document::new(lines))
     }
 
     pub fn new(lines: Vec<String>) -> Self {
         let items = parse_items(&lines);
         let imports = get_imports(&lines);
+        let line_offsets = line_offsets(&lines);
         Document {
             lines,
             items,
             imports,
+            line_offsets,
+        }
+    }
+
+    /// Translate an LSP `Position` (UTF-16 based) into a byte offset into the
+    /// buffer formed by joining `lines` with `\n`.
+    fn position_to_offset(&self, pos: Position) -> usize {
+        let line_start = self.line_offsets[pos.line as usize];
+        let line = &self.lines[pos.line as usize];
+        let mut utf16 = 0u32;
+        for (byte_idx, ch) in line.char_indices() {
+            if utf16 >= pos.character {
+                return line_start + byte_idx;
+            }
+            utf16 += ch.len_utf16() as u32;
         }
+        line_start + line.len()
+    }
+
+    /// Apply a single incremental content change, splicing `change.text` into
+    /// the buffer at `change.range` and reparsing. Falls back to a full
+    /// reparse when the change carries no range (full-document sync).
+    pub fn apply_change(&mut self, change: &TextDocumentContentChangeEvent) {
+        let Some(range) = change.range else {
+            *self = Document::new(change.text.split('\n').map(String::from).collect());
+            return;
+        };
+        let start = self.position_to_offset(range.start);
+        let end = self.position_to_offset(range.end);
+        let mut buffer = self.lines.join("\n");
+        buffer.replace_range(start..end, &change.text);
+        // `split`, not `lines`, so a trailing `\n` keeps its trailing empty
+        // line and the line table stays aligned with the client's.
+        *self = Document::new(buffer.split('\n').map(String::from).collect());
     }
 
     pub fn get_documentation(&self, item: &HareItem) -> Option<String> {
         let item_line = item.range.start.line as usize;
+        let (start, end) = self.comment_lines_before(item_line)?;
+        Some(
+            self.lines[start..=end]
+                .iter()
+                .flat_map(|line| [&line[2..], "\n"])
+                .collect(),
+        )
+    }
+
+    /// Line range (inclusive) of the run of consecutive `//` comment lines
+    /// immediately preceding `item_line`, if any.
+    fn comment_lines_before(&self, item_line: usize) -> Option<(usize, usize)> {
         let start = self.lines[..item_line]
             .iter()
             .rposition(|line| !line.starts_with("//"))?;
-        if start + 1 < item_line {
-            Some(
-                self.lines[start + 1..item_line]
-                    .iter()
-                    .flat_map(|line| [&line[2..], "\n"])
-                    .collect(),
-            )
-        } else {
-            None
+        (start + 1 < item_line).then_some((start + 1, item_line - 1))
+    }
+
+    /// Folding ranges for this document: the leading `use` block, the
+    /// comment block preceding each item, and the body of each `fn`/`type`.
+    pub fn folding_ranges(&self) -> Vec<FoldingRange> {
+        let mut ranges = Vec::new();
+        ranges.extend(self.imports_fold());
+        for item in &self.items {
+            let item_line = item.range.start.line as usize;
+            if let Some((start, end)) = self.comment_lines_before(item_line) {
+                ranges.push(FoldingRange {
+                    start_line: start as u32,
+                    end_line: end as u32,
+                    kind: Some(FoldingRangeKind::Comment),
+                    ..Default::default()
+                });
+            }
+            if matches!(item.kind, HareKind::Fn | HareKind::Type) {
+                ranges.extend(self.item_body_fold(item));
+            }
         }
+        ranges
+    }
+
+    fn imports_fold(&self) -> Option<FoldingRange> {
+        let start = self
+            .lines
+            .iter()
+            .position(|l| l.strip_prefix("use").is_some())?;
+        let len = self.lines[start..]
+            .iter()
+            .take_while(|l| l.strip_prefix("use").is_some())
+            .count();
+        (len > 1).then(|| FoldingRange {
+            start_line: start as u32,
+            end_line: (start + len - 1) as u32,
+            kind: Some(FoldingRangeKind::Imports),
+            ..Default::default()
+        })
+    }
+
+    /// Fold from `item`'s declaration line to the line where its matching
+    /// closing brace is found, tracking brace depth through the lines in
+    /// between (braces inside comments or string/char literals don't count).
+    fn item_body_fold(&self, item: &HareItem) -> Option<FoldingRange> {
+        let start_line = item.range.start.line as usize;
+        let mut depth = 0i32;
+        let mut started = false;
+        for (i, line) in self.lines.iter().enumerate().skip(start_line) {
+            for ch in code_chars(line) {
+                match ch {
+                    '{' => {
+                        depth += 1;
+                        started = true;
+                    }
+                    '}' => depth -= 1,
+                    // Declaration ends before any body brace: nothing to fold.
+                    ';' if !started => return None,
+                    _ => {}
+                }
+            }
+            if started && depth <= 0 {
+                return Some(FoldingRange {
+                    start_line: start_line as u32,
+                    end_line: i as u32,
+                    kind: Some(FoldingRangeKind::Region),
+                    ..Default::default()
+                });
+            }
+        }
+        None
     }
 }
 
@@ -137,7 +240,63 @@ pub fn get_imports(source: &[String]) -> HashSet<Ident> {
         .collect()
 }
 
-pub fn get_identifier(line: &str, char_idx: u32) -> Ident {
+/// Marks each byte offset of `line` as real code (`true`) or not (`false`):
+/// bytes inside a trailing `//` comment or a string/char literal are
+/// excluded, so callers can ignore braces or identifiers that only appear
+/// in comments or literal text.
+pub fn code_mask(line: &str) -> Vec<bool> {
+    let mut mask = vec![true; line.len()];
+    let mut in_string: Option<char> = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if let Some(quote) = in_string {
+            mask[i..i + c.len_utf8()].fill(false);
+            if c == '\\' {
+                if let Some(&(j, escaped)) = chars.peek() {
+                    mask[j..j + escaped.len_utf8()].fill(false);
+                    chars.next();
+                }
+            } else if c == quote {
+                in_string = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => {
+                in_string = Some(c);
+                mask[i..i + c.len_utf8()].fill(false);
+            }
+            '/' if chars.peek().is_some_and(|&(_, next)| next == '/') => {
+                mask[i..].fill(false);
+                break;
+            }
+            _ => {}
+        }
+    }
+    mask
+}
+
+/// Characters of `line` that are actual code, in source order: those not
+/// excluded by [`code_mask`].
+fn code_chars(line: &str) -> impl Iterator<Item = char> + '_ {
+    let mask = code_mask(line);
+    line.char_indices()
+        .filter(move |&(i, _)| mask[i])
+        .map(|(_, c)| c)
+}
+
+fn line_offsets(lines: &[String]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(lines.len());
+    let mut offset = 0usize;
+    for line in lines {
+        offsets.push(offset);
+        offset += line.len() + 1;
+    }
+    offsets
+}
+
+/// Byte range of the identifier touching `char_idx` in `line`.
+pub fn identifier_span(line: &str, char_idx: u32) -> (usize, usize) {
     let i = char_idx as usize;
     let start = line[..i]
         .rfind(|c: char| !(c.is_alphanumeric() || c == ':' || c == '_'))
@@ -147,9 +306,26 @@ pub fn get_identifier(line: &str, char_idx: u32) -> Ident {
         .find(|c: char| !(c.is_alphanumeric() || c == '_'))
         .map(|j| i + j)
         .unwrap_or(line.len());
+    (start, end)
+}
+
+pub fn get_identifier(line: &str, char_idx: u32) -> Ident {
+    let (start, end) = identifier_span(line, char_idx);
     line[start..end]
         .trim_end_matches(':')
         .split("::")
         .map(SmolStr::from)
         .collect()
 }
+
+/// Byte offsets where a new identifier begins in `line` (the start of the
+/// line, or right after a character that cannot itself be part of one).
+pub fn identifier_starts(line: &str) -> impl Iterator<Item = u32> + '_ {
+    let mut prev_is_ident = false;
+    line.char_indices().filter_map(move |(i, c)| {
+        let is_ident = c.is_alphanumeric() || c == '_';
+        let is_start = is_ident && !prev_is_ident;
+        prev_is_ident = is_ident;
+        is_start.then_some(i as u32)
+    })
+}
@@ -0,0 +1,39 @@
+//! A small subsequence-based fuzzy matcher used for workspace symbol search.
+
+/// Score `candidate` against `query`, or `None` if `query`'s characters do
+/// not all appear in `candidate`, in order (case-insensitively). Higher is a
+/// better match: matches at word boundaries (the start of `candidate`, or
+/// right after `_`) and consecutive runs are rewarded, gaps between matched
+/// characters are penalized, and shorter candidates are preferred on ties.
+pub fn fuzzy_match(query: &str, candidate: &str) -> Option<i32> {
+    if query.is_empty() {
+        return Some(0);
+    }
+    let query = query.as_bytes();
+    let candidate_bytes = candidate.as_bytes();
+    let mut qi = 0;
+    let mut score = 0i32;
+    let mut last_matched: Option<usize> = None;
+    for (ci, &b) in candidate_bytes.iter().enumerate() {
+        if qi >= query.len() {
+            break;
+        }
+        if b.to_ascii_lowercase() == query[qi].to_ascii_lowercase() {
+            if ci == 0 || candidate_bytes[ci - 1] == b'_' {
+                score += 10;
+            }
+            match last_matched {
+                Some(prev) if prev + 1 == ci => score += 5,
+                Some(_) => score -= 1,
+                None => {}
+            }
+            last_matched = Some(ci);
+            qi += 1;
+        }
+    }
+    if qi < query.len() {
+        return None;
+    }
+    score -= candidate.len() as i32;
+    Some(score)
+}
@@ -2,21 +2,32 @@
 #![allow(clippy::mutable_key_type)]
 
 mod doc;
-use crate::doc::{get_identifier, Document, HareItem, Ident};
+mod fuzzy;
+mod module_index;
+use crate::doc::{
+    code_mask, get_identifier, identifier_span, identifier_starts, Document, HareItem, Ident,
+};
+use crate::module_index::ModuleIndex;
 
 use std::{
     collections::{HashMap, HashSet},
-    path::{Component, Path, PathBuf},
+    path::{Path, PathBuf},
 };
 
 use lsp_server::{Connection, Message, Response};
 use lsp_types::{
     notification::{DidChangeTextDocument, DidOpenTextDocument, Notification},
-    request::{Completion, GotoDefinition, HoverRequest, Request},
+    request::{
+        Completion, DocumentSymbolRequest, FoldingRangeRequest, GotoDefinition, HoverRequest,
+        References, Request, WorkspaceSymbolRequest,
+    },
     CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, Documentation, GotoDefinitionParams,
-    GotoDefinitionResponse, Hover, HoverContents, HoverParams, Location, MarkedString, OneOf,
-    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+    DidChangeTextDocumentParams, DidOpenTextDocumentParams, DocumentSymbolParams,
+    DocumentSymbolResponse, Documentation, FoldingRange, FoldingRangeParams,
+    FoldingRangeProviderCapability, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, Location, MarkedString, OneOf, Position, Range, ReferenceParams,
+    ServerCapabilities, SymbolInformation, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Uri, WorkspaceSymbolParams, WorkspaceSymbolResponse,
 };
 use smol_str::SmolStr;
 
@@ -33,13 +44,20 @@ fn main() -> Result<(), DynError> {
             trigger_characters: Some(vec![":".into()]),
             ..Default::default()
         }),
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::INCREMENTAL,
+        )),
         hover_provider: Some(true.into()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        workspace_symbol_provider: Some(OneOf::Left(true)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        references_provider: Some(OneOf::Left(true)),
         ..Default::default()
     };
     let server_capabilities = serde_json::to_value(capabilities)?;
     let _initialization_params = conn.initialize(server_capabilities)?;
     let mut docs = HashMap::<Uri, Document>::new();
+    let mut module_index = ModuleIndex::new();
 
     let harepath: String = std::env::var("HAREPATH")
         .unwrap_or("/usr/local/src/hare/stdlib/:/usr/local/src/hare/third-party/".to_owned());
@@ -56,22 +74,46 @@ fn main() -> Result<(), DynError> {
                 match request.method.as_str() {
                     GotoDefinition::METHOD => {
                         let params = serde_json::from_value(request.params)?;
-                        let defs = find_definition(params, &docs);
+                        let defs = find_definition(params, &docs, &module_index);
                         conn.sender
                             .send(Response::new_ok(request.id, defs).into())?;
                     }
                     Completion::METHOD => {
                         let params = serde_json::from_value(request.params)?;
-                        let completions = generate_completions(params, &docs);
+                        let completions = generate_completions(params, &docs, &module_index);
                         conn.sender
                             .send(Response::new_ok(request.id, completions).into())?;
                     }
                     HoverRequest::METHOD => {
                         let params = serde_json::from_value(request.params)?;
-                        let hover = generate_hover(params, &docs);
+                        let hover = generate_hover(params, &docs, &module_index);
                         conn.sender
                             .send(Response::new_ok(request.id, hover).into())?;
                     }
+                    DocumentSymbolRequest::METHOD => {
+                        let params = serde_json::from_value(request.params)?;
+                        let symbols = document_symbols(params, &docs);
+                        conn.sender
+                            .send(Response::new_ok(request.id, symbols).into())?;
+                    }
+                    WorkspaceSymbolRequest::METHOD => {
+                        let params = serde_json::from_value(request.params)?;
+                        let symbols = workspace_symbols(params, &docs);
+                        conn.sender
+                            .send(Response::new_ok(request.id, symbols).into())?;
+                    }
+                    FoldingRangeRequest::METHOD => {
+                        let params = serde_json::from_value(request.params)?;
+                        let ranges = generate_folding_ranges(params, &docs);
+                        conn.sender
+                            .send(Response::new_ok(request.id, ranges).into())?;
+                    }
+                    References::METHOD => {
+                        let params = serde_json::from_value(request.params)?;
+                        let refs = find_references(params, &docs, &module_index);
+                        conn.sender
+                            .send(Response::new_ok(request.id, refs).into())?;
+                    }
                     _ => {
                         log::info!("ignoring request: {request:?}");
                     }
@@ -83,11 +125,11 @@ fn main() -> Result<(), DynError> {
             Message::Notification(notification) => match notification.method.as_str() {
                 DidOpenTextDocument::METHOD => {
                     let params = serde_json::from_value(notification.params)?;
-                    initialize_docs(params, &mut docs, &search_paths)?;
+                    initialize_docs(params, &mut docs, &mut module_index, &search_paths)?;
                 }
                 DidChangeTextDocument::METHOD => {
                     let params = serde_json::from_value(notification.params)?;
-                    update_docs(params, &mut docs, &search_paths)?;
+                    update_docs(params, &mut docs, &mut module_index, &search_paths)?;
                 }
                 _ => {
                     log::info!("ignoring notification: {notification:?}");
@@ -100,14 +142,18 @@ fn main() -> Result<(), DynError> {
     Ok(())
 }
 
-fn generate_hover(params: HoverParams, docs: &HashMap<Uri, Document>) -> Option<Hover> {
+fn generate_hover(
+    params: HoverParams,
+    docs: &HashMap<Uri, Document>,
+    module_index: &ModuleIndex,
+) -> Option<Hover> {
     let uri = params.text_document_position_params.text_document.uri;
     let loc = params.text_document_position_params.position;
     let doc_module = module_from_uri(&uri);
     if let Some(Document { lines, imports, .. }) = docs.get(&uri) {
         let ident = get_identifier(&lines[loc.line as usize], loc.character);
         let item_module = module_of_ident(&ident, &doc_module, imports);
-        for (_uri, module) in module_files(docs, item_module) {
+        for (_uri, module) in module_files(docs, module_index, item_module) {
             if let Some(item) = find_item(&module.items, &ident) {
                 return module.get_documentation(item).map(|d| Hover {
                     contents: HoverContents::Scalar(MarkedString::String(d)),
@@ -119,9 +165,12 @@ fn generate_hover(params: HoverParams, docs: &HashMap<Uri, Document>) -> Option<
     None
 }
 
+const AUTO_IMPORT_LIMIT: usize = 50;
+
 fn generate_completions(
     params: CompletionParams,
     docs: &HashMap<Uri, Document>,
+    module_index: &ModuleIndex,
 ) -> CompletionResponse {
     let uri = params.text_document_position.text_document.uri;
     let loc = params.text_document_position.position;
@@ -130,7 +179,7 @@ fn generate_completions(
     if let Some(Document { lines, imports, .. }) = docs.get(&uri) {
         let ident = get_identifier(&lines[loc.line as usize], loc.character);
         let item_module = module_of_ident(&ident, &doc_module, imports);
-        for (_uri, module) in module_files(docs, item_module) {
+        for (_uri, module) in module_files(docs, module_index, item_module.clone()) {
             completions.extend(module.items.iter().map(|item| CompletionItem {
                 label: item.name.to_string(),
                 kind: match item.kind {
@@ -143,10 +192,139 @@ fn generate_completions(
                 ..Default::default()
             }));
         }
+        // A bare (unqualified) identifier can also be completed with exported
+        // items from modules that are indexed but not yet `use`d, inserting
+        // the missing `use` statement alongside the completion. Only once
+        // the user has typed something to match against, and ranked/bounded
+        // by that match, so this doesn't dump the whole stdlib on every
+        // keystroke.
+        if ident.len() == 1 && !ident[0].is_empty() {
+            let prefix = ident[0].as_str();
+            let imported_modules: HashSet<SmolStr> =
+                imports.iter().filter_map(|i| i.last().cloned()).collect();
+            let insert_pos = Position::new(use_insert_line(lines) as u32, 0);
+            let mut candidates: Vec<(i32, SmolStr, &Document, &HareItem)> = Vec::new();
+            for module_name in module_index.module_names() {
+                if *module_name == item_module || imported_modules.contains(module_name) {
+                    continue;
+                }
+                for (_uri, module) in module_files(docs, module_index, module_name.clone()) {
+                    candidates.extend(module.items.iter().filter(|item| item.exported).filter_map(
+                        |item| {
+                            fuzzy::fuzzy_match(prefix, &item.name)
+                                .map(|score| (score, module_name.clone(), module, item))
+                        },
+                    ));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.cmp(&a.0));
+            completions.extend(candidates.into_iter().take(AUTO_IMPORT_LIMIT).map(
+                |(_, module_name, module, item)| CompletionItem {
+                    label: item.name.to_string(),
+                    kind: match item.kind {
+                        doc::HareKind::Type => Some(CompletionItemKind::STRUCT),
+                        doc::HareKind::Fn => Some(CompletionItemKind::FUNCTION),
+                        doc::HareKind::Def => Some(CompletionItemKind::CONSTANT),
+                        doc::HareKind::Var => Some(CompletionItemKind::VARIABLE),
+                    },
+                    detail: Some(format!("{module_name}::{}", item.name)),
+                    documentation: module.get_documentation(item).map(Documentation::String),
+                    additional_text_edits: Some(vec![TextEdit {
+                        range: Range::new(insert_pos, insert_pos),
+                        new_text: format!("use {module_name};\n"),
+                    }]),
+                    ..Default::default()
+                },
+            ));
+        }
     }
     CompletionResponse::Array(completions)
 }
 
+/// Line number at which to insert a new `use` statement: right after the
+/// last existing `use` line, or at the top of the file if there are none.
+fn use_insert_line(lines: &[String]) -> usize {
+    lines
+        .iter()
+        .rposition(|l| l.strip_prefix("use").is_some())
+        .map_or(0, |i| i + 1)
+}
+
+const WORKSPACE_SYMBOL_LIMIT: usize = 100;
+
+fn symbol_kind(kind: doc::HareKind) -> SymbolKind {
+    match kind {
+        doc::HareKind::Type => SymbolKind::STRUCT,
+        doc::HareKind::Fn => SymbolKind::FUNCTION,
+        doc::HareKind::Def => SymbolKind::CONSTANT,
+        doc::HareKind::Var => SymbolKind::VARIABLE,
+    }
+}
+
+fn document_symbols(
+    params: DocumentSymbolParams,
+    docs: &HashMap<Uri, Document>,
+) -> Option<DocumentSymbolResponse> {
+    let uri = params.text_document.uri;
+    let doc = docs.get(&uri)?;
+    #[allow(deprecated)]
+    let symbols = doc
+        .items
+        .iter()
+        .map(|item| SymbolInformation {
+            name: item.name.to_string(),
+            kind: symbol_kind(item.kind),
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: uri.clone(),
+                range: item.range,
+            },
+            container_name: None,
+        })
+        .collect();
+    Some(DocumentSymbolResponse::Flat(symbols))
+}
+
+fn workspace_symbols(
+    params: WorkspaceSymbolParams,
+    docs: &HashMap<Uri, Document>,
+) -> WorkspaceSymbolResponse {
+    let mut matches = Vec::new();
+    for (uri, module) in docs.iter() {
+        for item in module.items.iter().filter(|item| item.exported) {
+            if let Some(score) = fuzzy::fuzzy_match(&params.query, &item.name) {
+                matches.push((score, uri, item));
+            }
+        }
+    }
+    matches.sort_by(|a, b| b.0.cmp(&a.0));
+    #[allow(deprecated)]
+    let symbols = matches
+        .into_iter()
+        .take(WORKSPACE_SYMBOL_LIMIT)
+        .map(|(_, uri, item)| SymbolInformation {
+            name: item.name.to_string(),
+            kind: symbol_kind(item.kind),
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: uri.clone(),
+                range: item.range,
+            },
+            container_name: Some(module_from_uri(uri)),
+        })
+        .collect();
+    WorkspaceSymbolResponse::Flat(symbols)
+}
+
+fn generate_folding_ranges(
+    params: FoldingRangeParams,
+    docs: &HashMap<Uri, Document>,
+) -> Option<Vec<FoldingRange>> {
+    Some(docs.get(&params.text_document.uri)?.folding_ranges())
+}
+
 fn module_of_ident(ident: &Ident, current_module: &str, imports: &HashSet<Ident>) -> SmolStr {
     let resolved_ident = resolve_ident(current_module, &ident, imports);
     let item_module = &resolved_ident[resolved_ident.len().saturating_sub(2)];
@@ -156,6 +334,7 @@ fn module_of_ident(ident: &Ident, current_module: &str, imports: &HashSet<Ident>
 fn find_definition(
     params: GotoDefinitionParams,
     docs: &HashMap<Uri, Document>,
+    module_index: &ModuleIndex,
 ) -> GotoDefinitionResponse {
     let uri = params.text_document_position_params.text_document.uri;
     let loc = params.text_document_position_params.position;
@@ -166,7 +345,7 @@ fn find_definition(
             let ident = get_identifier(line, loc.character);
             let resolved_ident = resolve_ident(doc_module.as_str(), &ident, imports);
             let item_module = &resolved_ident[resolved_ident.len().saturating_sub(2)];
-            for (uri, content) in module_files(docs, item_module.clone()) {
+            for (uri, content) in module_files(docs, module_index, item_module.clone()) {
                 if let Some(item) = find_item(&content.items, &ident) {
                     locations.push(Location {
                         uri: uri.clone(),
@@ -183,6 +362,71 @@ fn find_definition(
     }
 }
 
+fn find_references(
+    params: ReferenceParams,
+    docs: &HashMap<Uri, Document>,
+    module_index: &ModuleIndex,
+) -> Vec<Location> {
+    let uri = params.text_document_position.text_document.uri;
+    let loc = params.text_document_position.position;
+    let doc_module = module_from_uri(&uri);
+    let mut locations = Vec::new();
+    let Some(Document { lines, imports, .. }) = docs.get(&uri) else {
+        return locations;
+    };
+    let Some(line) = lines.get(loc.line as usize) else {
+        return locations;
+    };
+    let ident = get_identifier(line, loc.character);
+    let target = resolve_ident(doc_module.as_str(), &ident, imports);
+
+    let item_module = &target[target.len().saturating_sub(2)];
+    let declarations: Vec<(&Uri, Range)> = module_files(docs, module_index, item_module.clone())
+        .filter_map(|(def_uri, content)| {
+            find_item(&content.items, &ident).map(|item| (def_uri, item.range))
+        })
+        .collect();
+
+    if params.context.include_declaration {
+        locations.extend(declarations.iter().map(|&(uri, range)| Location {
+            uri: uri.clone(),
+            range,
+        }));
+    }
+
+    for (ref_uri, document) in docs.iter() {
+        let ref_module = module_from_uri(ref_uri);
+        for (line_no, line) in document.lines.iter().enumerate() {
+            let mask = code_mask(line);
+            for start in identifier_starts(line) {
+                if !mask[start as usize] {
+                    continue;
+                }
+                let candidate = get_identifier(line, start);
+                if resolve_ident(ref_module.as_str(), &candidate, &document.imports) != target {
+                    continue;
+                }
+                let (span_start, span_end) = identifier_span(line, start);
+                let range = Range::new(
+                    Position::new(line_no as u32, span_start as u32),
+                    Position::new(line_no as u32, span_end as u32),
+                );
+                // The item's own name in its declaration line resolves to
+                // `target` too; it's covered above (or skipped entirely)
+                // based on `include_declaration`, not re-added from the scan.
+                if declarations.contains(&(ref_uri, range)) {
+                    continue;
+                }
+                locations.push(Location {
+                    uri: ref_uri.clone(),
+                    range,
+                });
+            }
+        }
+    }
+    locations
+}
+
 fn find_item<'i>(items: &'i HashSet<HareItem>, ident: &Ident) -> Option<&'i HareItem> {
     let expected = ident.last().unwrap().clone();
     let local = ident.len() == 1;
@@ -218,13 +462,15 @@ fn resolve_ident(current_module: &str, ident: &Ident, imports: &HashSet<Ident>)
 pub fn initialize_docs(
     params: DidOpenTextDocumentParams,
     docs: &mut HashMap<Uri, Document>,
+    module_index: &mut ModuleIndex,
     search_paths: &[&str],
 ) -> Result<(), DynError> {
     let uri = params.text_document.uri;
     let root = Document::open(&uri)?;
-    add_docs_from_imports(docs, root.imports.iter(), search_paths)?;
+    add_docs_from_imports(docs, module_index, root.imports.iter(), search_paths)?;
     let doc_path = Path::new(uri.path().as_str());
-    add_docs_from_directory(docs, doc_path.parent().unwrap())?;
+    add_docs_from_directory(docs, module_index, doc_path.parent().unwrap())?;
+    module_index.insert(&uri);
     docs.insert(uri, root);
     Ok(())
 }
@@ -232,41 +478,32 @@ pub fn initialize_docs(
 pub fn update_docs(
     params: DidChangeTextDocumentParams,
     docs: &mut HashMap<Uri, Document>,
+    module_index: &mut ModuleIndex,
     search_paths: &[&str],
 ) -> Result<(), DynError> {
     let uri = params.text_document.uri;
     log::info!("{:?}", params.content_changes);
-    if let Some(doc) = docs.remove(&uri) {
-        // let mut lines = doc.lines;
-        // for change in params.content_changes.iter() {
-        //     let range = change.range.unwrap_or_default();
-        //     let start = range.start.line as usize;
-        //     let end = range.end.line as usize;
-        //     assert_eq!(range.start.character, 0);
-        //     assert_eq!(range.end.character, 0);
-        //     let changed_lines = change.text.lines().map(String::from);
-        //     lines.splice(start..end, changed_lines);
-        // }
-        assert!(params.content_changes.len() == 1);
-        let updated_doc = Document::new(
-            params.content_changes[0]
-                .text
-                .lines()
-                .map(String::from)
-                .collect(),
-        );
+    if let Some(mut doc) = docs.remove(&uri) {
+        module_index.remove(&uri);
+        let old_imports = doc.imports.clone();
+        for change in &params.content_changes {
+            doc.apply_change(change);
+        }
         add_docs_from_imports(
             docs,
-            updated_doc.imports.difference(&doc.imports),
+            module_index,
+            doc.imports.difference(&old_imports),
             search_paths,
         )?;
-        docs.insert(uri, updated_doc);
+        module_index.insert(&uri);
+        docs.insert(uri, doc);
     };
     Ok(())
 }
 
 fn add_docs_from_directory(
     docs: &mut HashMap<Uri, Document>,
+    module_index: &mut ModuleIndex,
     dir_path: &Path,
 ) -> Result<(), DynError> {
     if dir_path.is_dir() {
@@ -280,6 +517,7 @@ fn add_docs_from_directory(
             if entry_path.is_file() && entry_path.extension().is_some_and(|ext| ext == "ha") {
                 let uri = path_to_uri(&entry_path)?;
                 if let Ok(doc) = Document::open(&uri) {
+                    module_index.insert(&uri);
                     docs.insert(uri, doc);
                 } else {
                     eprintln!("WARNING: could not open: {}", entry_path.display());
@@ -290,7 +528,7 @@ fn add_docs_from_directory(
                     .is_some_and(|name| name.as_encoded_bytes().starts_with(b"+"))
             {
                 // log::info!("indexing subdirectory: {}", entry_path.display());
-                add_docs_from_directory(docs, &entry_path)?;
+                add_docs_from_directory(docs, module_index, &entry_path)?;
             }
         }
     }
@@ -299,6 +537,7 @@ fn add_docs_from_directory(
 
 fn add_docs_from_imports<'i, I: Iterator<Item = &'i Ident>>(
     docs: &mut HashMap<Uri, Document>,
+    module_index: &mut ModuleIndex,
     imports: I,
     search_paths: &[&str],
 ) -> Result<(), DynError> {
@@ -306,38 +545,20 @@ fn add_docs_from_imports<'i, I: Iterator<Item = &'i Ident>>(
         for path in search_paths.iter() {
             let mut module_path = PathBuf::from(path);
             module_path.extend(import);
-            add_docs_from_directory(docs, &module_path)?;
+            add_docs_from_directory(docs, module_index, &module_path)?;
         }
     }
     Ok(())
 }
 
-fn module_files(
-    docs: &HashMap<Uri, Document>,
+fn module_files<'d>(
+    docs: &'d HashMap<Uri, Document>,
+    module_index: &'d ModuleIndex,
     current_module: SmolStr,
-) -> impl Iterator<Item = (&Uri, &Document)> {
-    docs.iter().filter_map(move |(k, v)| {
-        let path = Path::new(k.path().as_str());
-        if path.extension().is_none_or(|ext| ext != "ha") {
-            return None;
-        }
-        let mut comps = path.components().rev();
-        let _filename = comps.next()?;
-        // current_module/foo.ha
-        if let Component::Normal(parent) = comps.next()? {
-            if parent == current_module.as_str() {
-                return Some((k, v));
-            }
-            if parent.as_encoded_bytes().starts_with(b"+") {
-                if let Component::Normal(parent) = comps.next()? {
-                    if parent == current_module.as_str() {
-                        return Some((k, v));
-                    }
-                }
-            }
-        }
-        None
-    })
+) -> impl Iterator<Item = (&'d Uri, &'d Document)> {
+    module_index
+        .uris(&current_module)
+        .filter_map(|uri| docs.get(uri).map(|doc| (uri, doc)))
 }
 
 fn module_from_uri(uri: &Uri) -> String {